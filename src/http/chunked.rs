@@ -0,0 +1,123 @@
+// Decoder/encoder for HTTP/1.1 "Transfer-Encoding: chunked" framing
+// (RFC 7230 section 4.1). `copy_chunked` reads a chunked body from a
+// socket and re-emits it, chunk by chunk, so callers don't need to
+// buffer the whole body to forward it.
+
+use std::io::prelude::*;
+use std::io;
+use std::str;
+
+const CRLF: &'static [u8] = b"\r\n";
+
+/// Reads a chunked-encoded body from `source` and re-chunks it onto
+/// `sink`, stopping once the terminating `0\r\n\r\n` chunk is consumed.
+pub fn copy_chunked<R: Read, W: Write>(source: &mut R, sink: &mut W) -> io::Result<()> {
+    loop {
+        let size = try!(read_chunk_size(source));
+
+        if size == 0 {
+            // The final chunk has no data, just a trailer (which we don't
+            // support forwarding) and the closing CRLF. Skip trailer lines up
+            // to and including the empty one.
+            loop {
+                if try!(read_line(source)).is_empty() {
+                    break;
+                }
+            }
+            return write_final_chunk(sink);
+        }
+
+        let mut chunk = vec![0; size];
+        try!(source.read_exact(&mut chunk));
+        // Every chunk's data is followed by a CRLF before the next size line.
+        try!(read_line(source));
+
+        try!(write_chunk(sink, &chunk));
+    }
+}
+
+fn write_chunk<W: Write>(sink: &mut W, chunk: &[u8]) -> io::Result<()> {
+    try!(sink.write_all(format!("{:x}\r\n", chunk.len()).as_bytes()));
+    try!(sink.write_all(chunk));
+    sink.write_all(CRLF)
+}
+
+fn write_final_chunk<W: Write>(sink: &mut W) -> io::Result<()> {
+    sink.write_all(b"0\r\n\r\n")
+}
+
+// Reads a single CRLF-terminated line, without the trailing CRLF.
+fn read_line<R: Read>(source: &mut R) -> io::Result<Vec<u8>> {
+    let mut line = Vec::new();
+    let mut byte = [0; 1];
+
+    loop {
+        try!(source.read_exact(&mut byte));
+        line.push(byte[0]);
+
+        if line.ends_with(CRLF) {
+            let len = line.len() - CRLF.len();
+            line.truncate(len);
+            return Ok(line);
+        }
+    }
+}
+
+// Chunk-size lines look like `<hex size>[;ext...]\r\n`. We don't act on
+// chunk extensions, just skip over them.
+fn read_chunk_size<R: Read>(source: &mut R) -> io::Result<usize> {
+    let line = try!(read_line(source));
+    let size_bytes = match line.iter().position(|&b| b == b';') {
+        Some(pos) => &line[..pos],
+        None => &line[..],
+    };
+
+    let size_str = try!(str::from_utf8(size_bytes)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid chunk size")));
+
+    usize::from_str_radix(size_str.trim(), 16)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid chunk size"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn copy(input: &[u8]) -> Vec<u8> {
+        let mut source = Cursor::new(input.to_vec());
+        let mut out = Vec::new();
+        copy_chunked(&mut source, &mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn test_single_chunk() {
+        assert_eq!(copy(b"4\r\nWiki\r\n0\r\n\r\n"), b"4\r\nWiki\r\n0\r\n\r\n".to_vec());
+    }
+
+    #[test]
+    fn test_multiple_chunks() {
+        let input = b"6\r\nfoobar\r\n3\r\nbaz\r\n0\r\n\r\n";
+        assert_eq!(copy(input), input.to_vec());
+    }
+
+    #[test]
+    fn test_empty_body() {
+        assert_eq!(copy(b"0\r\n\r\n"), b"0\r\n\r\n".to_vec());
+    }
+
+    #[test]
+    fn test_chunk_extension_is_dropped() {
+        // The extension isn't retained by `copy_chunked`, so the re-emitted
+        // chunk size line is shorter than the input's.
+        assert_eq!(copy(b"4;foo=bar\r\nWiki\r\n0\r\n\r\n"), b"4\r\nWiki\r\n0\r\n\r\n".to_vec());
+    }
+
+    #[test]
+    fn test_invalid_chunk_size_errors() {
+        let mut source = Cursor::new(b"zz\r\n".to_vec());
+        let mut out = Vec::new();
+        assert!(copy_chunked(&mut source, &mut out).is_err());
+    }
+}
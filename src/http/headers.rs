@@ -1,4 +1,5 @@
 extern crate httparse;
+extern crate fnv;
 
 use std::io;
 use std::str;
@@ -6,6 +7,42 @@ use std::collections::{HashMap, LinkedList};
 use std::collections::hash_map::Entry;
 use std::clone::Clone;
 
+// Header names are short, so the quality SipHash brings to the table isn't
+// worth its cost here - FNV is much cheaper for tiny keys.
+type HeaderMap = HashMap<String, LinkedList<OctopusHeader>, fnv::FnvBuildHasher>;
+
+// Most header names fit comfortably inside this many bytes, so lookups can
+// lowercase them without a heap allocation. Longer names fall back to a
+// heap-allocated String. `insert` still allocates, since the map owns its
+// keys.
+const STACK_NAME_CAPACITY: usize = 32;
+
+enum LowerName {
+    Stack([u8; STACK_NAME_CAPACITY], usize),
+    Heap(String),
+}
+
+impl LowerName {
+    fn new(name: &str) -> LowerName {
+        if name.len() <= STACK_NAME_CAPACITY && name.is_ascii() {
+            let mut buf = [0u8; STACK_NAME_CAPACITY];
+            for (dest, src) in buf.iter_mut().zip(name.bytes()) {
+                *dest = src.to_ascii_lowercase();
+            }
+            LowerName::Stack(buf, name.len())
+        } else {
+            LowerName::Heap(name.to_lowercase())
+        }
+    }
+
+    fn as_str(&self) -> &str {
+        match *self {
+            LowerName::Stack(ref buf, len) => str::from_utf8(&buf[..len]).unwrap(),
+            LowerName::Heap(ref s) => s,
+        }
+    }
+}
+
 pub const DEFAULT_INTO_BUFFER_CAPACITY: usize = 65536;
 pub const DEFAULT_HEADER_ROW_CAPACITY: usize = 256;
 
@@ -15,13 +52,28 @@ const HEADER_EXTRA_BYTES: usize = 4;
 const HEADER_SEPARATOR: &'static [u8] = b": ";
 const HEADER_NEWLINE: &'static [u8] = b"\r\n";
 
+// A header name must be a valid RFC 7230 `token` - this is what actually
+// needs validating on insert, since the value itself is allowed to be
+// arbitrary bytes.
+fn is_valid_header_name(name: &str) -> bool {
+    !name.is_empty() && name.bytes().all(is_token_byte)
+}
+
+fn is_token_byte(b: u8) -> bool {
+    match b {
+        b'!' | b'#' | b'$' | b'%' | b'&' | b'\'' | b'*' | b'+' | b'-' | b'.' |
+        b'^' | b'_' | b'`' | b'|' | b'~' => true,
+        b'0'...b'9' | b'a'...b'z' | b'A'...b'Z' => true,
+        _ => false,
+    }
+}
+
 #[derive(Debug)]
 struct OctopusHeader {
     // Original header name with case intact. This is different to the keys in
     // the main header listing which are normalized.
     original_name: String,
     value: Vec<u8>,
-    value_str: String,
 
     // Which header was this in the original request/response? 0 is first, 1 is
     // second, and so on.
@@ -37,7 +89,6 @@ impl OctopusHeader {
         OctopusHeader {
             original_name: original,
             value: contents.clone(),
-            value_str: String::from_utf8(contents.clone()).unwrap(),
             order: order,
             length_hint: length_hint,
         }
@@ -47,8 +98,12 @@ impl OctopusHeader {
         &self.value
     }
 
-    pub fn value_str<'a>(&'a self) -> &'a String {
-        &self.value_str
+    // Lazily attempts a UTF-8 decode of the stored value. Header values
+    // aren't guaranteed to be valid UTF-8 (cookies, auth tokens, and
+    // malformed upstream data all happily carry raw bytes), so this never
+    // panics - callers that need text get `None` for the rest.
+    pub fn value_str<'a>(&'a self) -> Option<&'a str> {
+        str::from_utf8(&self.value).ok()
     }
 
     pub fn original_name<'a>(&'a self) -> &'a String {
@@ -69,7 +124,6 @@ impl Clone for OctopusHeader {
         OctopusHeader {
             original_name: self.original_name().clone(),
             value: self.value().clone(),
-            value_str: self.value_str().clone(),
             order: self.order,
             length_hint: self.length_hint,
         }
@@ -78,7 +132,6 @@ impl Clone for OctopusHeader {
     fn clone_from(&mut self, source: &Self) {
         self.original_name = source.original_name().clone();
         self.value = source.value().clone();
-        self.value_str = source.value_str().clone();
         self.order = source.order;
         self.length_hint = source.length_hint;
     }
@@ -86,14 +139,14 @@ impl Clone for OctopusHeader {
 
 #[derive(Debug)]
 pub struct Headers {
-    data: HashMap<String, LinkedList<OctopusHeader>>,
+    data: HeaderMap,
     total_count: usize,
 }
 
 impl<'a> Headers {
     pub fn new() -> Headers {
         Headers {
-            data: HashMap::new(),
+            data: HeaderMap::default(),
             total_count: 0,
         }
     }
@@ -103,7 +156,7 @@ impl<'a> Headers {
         headers.total_count = raw.len();
 
         for header in raw {
-            headers.insert(header.name, &(header.value.iter().cloned().collect()));
+            try!(headers.insert(header.name, &(header.value.iter().cloned().collect())));
         }
 
         // Perform some basic verification.
@@ -117,15 +170,15 @@ impl<'a> Headers {
     pub fn content_length(&self) -> Option<usize> {
         match self.get("content-length") {
             Some(value) => {
-                Some(str::from_utf8(value).unwrap().parse().unwrap())
+                str::from_utf8(value).ok().and_then(|s| s.parse().ok())
             },
             None => None
         }
     }
 
     pub fn get(&'a self, name: &str) -> Option<&'a Vec<u8>> {
-        let name_lower = String::from(name).to_lowercase();
-        match self.data.get(&name_lower) {
+        let lower = LowerName::new(name);
+        match self.data.get(lower.as_str()) {
             Some(headers) => {
                 match headers.front() {
                     Some(header) => Some(header.value()),
@@ -136,10 +189,41 @@ impl<'a> Headers {
         }
     }
 
-    pub fn insert(&mut self, name: &str, value: &Vec<u8>) {
+    // Yields every value stored under `name`, in the order they appeared in
+    // the original request/response. Unlike `get`, this doesn't lose
+    // repeated headers (multiple `Set-Cookie`, `Via`, `Forwarded`, etc).
+    pub fn get_all<'b>(&'b self, name: &str) -> impl Iterator<Item=&'b Vec<u8>> {
+        let lower = LowerName::new(name);
+        self.data.get(lower.as_str())
+            .into_iter()
+            .flat_map(|list| list.iter().map(OctopusHeader::value))
+    }
+
+    // Joins every occurrence of `name` into a single comma-separated value,
+    // as RFC 7230 3.2.2 says is equivalent to repeating a header. Returns
+    // `None` if the header wasn't present at all.
+    pub fn get_combined(&self, name: &str) -> Option<String> {
+        let mut values = self.get_all(name).peekable();
+        if values.peek().is_none() {
+            return None;
+        }
+
+        let joined = values.map(|v| String::from_utf8_lossy(v).into_owned())
+            .collect::<Vec<String>>()
+            .join(", ");
+
+        Some(joined)
+    }
+
+    pub fn insert(&mut self, name: &str, value: &Vec<u8>) -> io::Result<()> {
+        if !is_valid_header_name(name) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                       format!("invalid header name: {:?}", name)));
+        }
+
         // Lowercase the header name for easier matching.
         let name_string = String::from(name);
-        let mut item = match self.data.entry(name_string.to_lowercase()) {
+        let item = match self.data.entry(name_string.to_lowercase()) {
             Entry::Occupied(entry) => {
                 entry.into_mut()
             },
@@ -150,6 +234,15 @@ impl<'a> Headers {
 
         item.push_back(OctopusHeader::new(name_string, value, self.total_count));
         self.total_count += 1;
+
+        Ok(())
+    }
+
+    // Drops every occurrence of `name`. Returns whether anything was
+    // actually removed.
+    pub fn remove(&mut self, name: &str) -> bool {
+        let lower = LowerName::new(name);
+        self.data.remove(lower.as_str()).is_some()
     }
 
     fn validate(&self) -> bool {
@@ -166,6 +259,16 @@ impl<'a> Headers {
         host_ok && length_ok
     }
 
+    // Yields the serialized header rows, ready to be appended after a
+    // request/status line. The blank line that ends the header block is left
+    // off; callers append it themselves.
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut out = self.to_utf8();
+        let len = out.len() - HEADER_NEWLINE.len();
+        out.truncate(len);
+        out
+    }
+
     // Yields the UTF-8 version of the headers without a move.
     fn to_utf8(&self) -> Vec<u8> {
         // TODO: self.total_count and self.data should be protected by mutexes
@@ -231,7 +334,7 @@ mod tests {
         let mut headers = Headers::new();
         let test_value: Vec<u8> = "Test-Value".as_bytes().iter().cloned().collect();
         for _ in 0..DEFAULT_INTO_BUFFER_CAPACITY {
-            headers.insert("Test-Header", &test_value);
+            headers.insert("Test-Header", &test_value).unwrap();
         }
 
         headers
@@ -252,7 +355,7 @@ mod tests {
 
         let value: Vec<u8> = "google.com".as_bytes().iter().cloned().collect();
 
-        headers.insert("Host", &value);
+        headers.insert("Host", &value).unwrap();
 
         let host_result = headers.get("Host");
         assert!(host_result.is_some());
@@ -267,12 +370,41 @@ mod tests {
         let value1: Vec<u8> = "1234".as_bytes().iter().cloned().collect();
         let value2: Vec<u8> = "5678".as_bytes().iter().cloned().collect();
 
-        headers.insert("Content-Length", &value1);
-        headers.insert("Content-Length", &value2);
+        headers.insert("Content-Length", &value1).unwrap();
+        headers.insert("Content-Length", &value2).unwrap();
 
         assert_eq!(headers.content_length(), Some(1234));
     }
 
+    #[test]
+    fn test_get_all() {
+        let mut headers = Headers::new();
+
+        let cookie1: Vec<u8> = "a=1".as_bytes().iter().cloned().collect();
+        let cookie2: Vec<u8> = "b=2".as_bytes().iter().cloned().collect();
+
+        headers.insert("Set-Cookie", &cookie1).unwrap();
+        headers.insert("Set-Cookie", &cookie2).unwrap();
+
+        let values: Vec<&Vec<u8>> = headers.get_all("set-cookie").collect();
+        assert_eq!(values, vec![&cookie1, &cookie2]);
+        assert_eq!(headers.get_all("x-missing").count(), 0);
+    }
+
+    #[test]
+    fn test_get_combined() {
+        let mut headers = Headers::new();
+
+        let first: Vec<u8> = "no-cache".as_bytes().iter().cloned().collect();
+        let second: Vec<u8> = "no-store".as_bytes().iter().cloned().collect();
+
+        headers.insert("Cache-Control", &first).unwrap();
+        headers.insert("Cache-Control", &second).unwrap();
+
+        assert_eq!(headers.get_combined("cache-control"), Some("no-cache, no-store".to_string()));
+        assert_eq!(headers.get_combined("x-missing"), None);
+    }
+
     #[test]
     fn test_good_parse() {
         let headers_buf = b"Host: foo.bar\r\nContent-Length: 10\r\nAccept: *\r\n\r\n";
@@ -376,4 +508,58 @@ mod bench {
             test::black_box(headers.get("most"))
         });
     }
+
+    // Exercises the heap-fallback path in `LowerName` for header names past
+    // `STACK_NAME_CAPACITY`, so the FNV/no-alloc win on `get` doesn't go
+    // unmeasured on the less common long-name case.
+    #[bench]
+    fn successful_get_long_name(b: &mut Bencher) {
+        let mut headers = Headers::new();
+        let value: Vec<u8> = "yes".as_bytes().iter().cloned().collect();
+        headers.insert("X-A-Rather-Long-Custom-Header-Name", &value).unwrap();
+
+        b.iter(|| {
+            test::black_box(headers.get("X-A-Rather-Long-Custom-Header-Name"))
+        });
+    }
+
+    #[bench]
+    fn unsuccessful_get_long_name(b: &mut Bencher) {
+        let mut headers = Headers::new();
+        let value: Vec<u8> = "yes".as_bytes().iter().cloned().collect();
+        headers.insert("X-A-Rather-Long-Custom-Header-Name", &value).unwrap();
+
+        b.iter(|| {
+            test::black_box(headers.get("X-Some-Other-Rather-Long-Header"))
+        });
+    }
+
+    // A name exactly `STACK_NAME_CAPACITY` bytes long, right at the edge of
+    // the stack/heap split, so the boundary case is measured rather than
+    // just the comfortably-short and comfortably-long cases above.
+    #[bench]
+    fn successful_get_at_stack_capacity_boundary(b: &mut Bencher) {
+        let name = "X-Header-Name-Exactly-32-Bytes--";
+        assert_eq!(name.len(), super::STACK_NAME_CAPACITY);
+
+        let mut headers = Headers::new();
+        let value: Vec<u8> = "yes".as_bytes().iter().cloned().collect();
+        headers.insert(name, &value).unwrap();
+
+        b.iter(|| {
+            test::black_box(headers.get(name))
+        });
+    }
+
+    // Mixed-case lookups force `LowerName` to actually lowercase the name
+    // rather than taking any already-lowercase fast path, so this measures
+    // the cost `get`/`successful_get` pay on the common case of a
+    // mixed-case header name coming in off the wire.
+    #[bench]
+    fn successful_get_mixed_case(b: &mut Bencher) {
+        let (_, headers) = tests::create_standard_headers();
+        b.iter(|| {
+            test::black_box(headers.get("HoSt"))
+        });
+    }
 }
@@ -0,0 +1,202 @@
+extern crate httparse;
+
+use std::io::prelude::*;
+use std::io;
+
+use ::headers::{Headers, DEFAULT_HEADER_ROW_CAPACITY};
+
+#[derive(Debug)]
+pub struct Response {
+    pub version: u8,
+    pub code: u16,
+    pub reason: String,
+    pub headers: Headers,
+}
+
+// How the body of a response is framed, so `forward` knows how much (if
+// any) of the upstream socket to copy rather than relying on the
+// connection closing.
+#[derive(Debug, PartialEq)]
+pub enum BodyFraming {
+    Length(usize),
+    Chunked,
+    Close,
+}
+
+impl Response {
+    pub fn from_raw(response: httparse::Response) -> io::Result<Response> {
+        let headers = try!(Headers::from_raw(response.headers));
+
+        Ok(Response {
+            version: response.version.unwrap(),
+            code: response.code.unwrap(),
+            reason: response.reason.unwrap().to_owned(),
+            headers: headers,
+        })
+    }
+
+    // Reads the status line and headers of a response off `upstream` into
+    // `buf`, growing it as needed, then parses it. Returns the parsed
+    // `Response` alongside the number of bytes of `buf` that were consumed
+    // by the status line and headers (anything past that is already-read
+    // body that the caller needs to account for). Unlike `httparse`'s own
+    // borrowed `Response`, this one owns its `reason` so it doesn't keep
+    // `buf` borrowed - callers need to read the leftover body bytes out of
+    // `buf` while the returned `Response` is still in use.
+    pub fn read_from<R: Read>(upstream: &mut R, buf: &mut Vec<u8>) -> io::Result<(Response, usize)> {
+        let mut chunk = [0; 4096];
+
+        loop {
+            let mut header_storage = [httparse::EMPTY_HEADER; DEFAULT_HEADER_ROW_CAPACITY];
+            let mut raw = httparse::Response::new(&mut header_storage);
+
+            match raw.parse(buf) {
+                Ok(httparse::Status::Complete(consumed)) => {
+                    let response = try!(Response::from_raw(raw));
+                    return Ok((response, consumed));
+                },
+                Ok(httparse::Status::Partial) => {
+                    let n = try!(upstream.read(&mut chunk));
+                    if n == 0 {
+                        return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                                   "upstream closed before response headers completed"));
+                    }
+                    buf.extend(&chunk[..n]);
+                },
+                Err(e) => {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, e.to_string()));
+                }
+            }
+        }
+    }
+
+    // FIXME: is there a serialization trait of some sort I can implement?
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut out = Vec::<u8>::with_capacity(65535);
+
+        let statusline = format!("HTTP/1.{} {} {}\r\n", self.version, self.code, self.reason);
+        out.extend(statusline.as_bytes());
+        out.extend(self.headers.serialize());
+        out.extend(b"\r\n");
+
+        out
+    }
+
+    // `is_head` should be true when this is the response to a HEAD request.
+    // Per RFC 7230 3.3.3, a response to HEAD, a 1xx, a 204, or a 304 never
+    // has a message body regardless of what `Content-Length` or
+    // `Transfer-Encoding` claim - treating those as framed by their stated
+    // length would have `forward` block forever waiting for body bytes
+    // that are never going to arrive.
+    pub fn framing(&self, is_head: bool) -> BodyFraming {
+        if is_head || self.never_has_body() {
+            BodyFraming::Length(0)
+        } else if self.is_chunked() {
+            BodyFraming::Chunked
+        } else {
+            match self.headers.content_length() {
+                Some(len) => BodyFraming::Length(len),
+                None => BodyFraming::Close,
+            }
+        }
+    }
+
+    fn never_has_body(&self) -> bool {
+        self.code < 200 || self.code == 204 || self.code == 304
+    }
+
+    fn is_chunked(&self) -> bool {
+        match self.headers.get("transfer-encoding") {
+            Some(value) => {
+                // Chunked is always the last coding applied, so checking the
+                // tail of the value is sufficient.
+                let lower: Vec<u8> = value.iter().map(|b| b.to_ascii_lowercase()).collect();
+                lower.ends_with(b"chunked")
+            },
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate httparse;
+
+    use super::*;
+    use ::headers::Headers;
+
+    fn response_with_headers(code: u16, headers: Headers) -> Response {
+        Response {
+            version: 1,
+            code: code,
+            reason: String::new(),
+            headers: headers,
+        }
+    }
+
+    fn headers_with_content_length(len: &str) -> Headers {
+        let mut headers = Headers::new();
+        let value: Vec<u8> = len.as_bytes().iter().cloned().collect();
+        headers.insert("Content-Length", &value).unwrap();
+        headers
+    }
+
+    #[test]
+    fn test_framing_respects_content_length() {
+        let response = response_with_headers(200, headers_with_content_length("42"));
+        assert_eq!(response.framing(false), BodyFraming::Length(42));
+    }
+
+    #[test]
+    fn test_framing_no_headers_closes() {
+        let response = response_with_headers(200, Headers::new());
+        assert_eq!(response.framing(false), BodyFraming::Close);
+    }
+
+    #[test]
+    fn test_framing_ignores_content_length_on_204() {
+        let response = response_with_headers(204, headers_with_content_length("42"));
+        assert_eq!(response.framing(false), BodyFraming::Length(0));
+    }
+
+    #[test]
+    fn test_framing_ignores_content_length_on_304() {
+        let response = response_with_headers(304, headers_with_content_length("42"));
+        assert_eq!(response.framing(false), BodyFraming::Length(0));
+    }
+
+    #[test]
+    fn test_framing_ignores_content_length_on_1xx() {
+        let response = response_with_headers(100, headers_with_content_length("42"));
+        assert_eq!(response.framing(false), BodyFraming::Length(0));
+    }
+
+    #[test]
+    fn test_framing_ignores_content_length_on_head() {
+        let response = response_with_headers(200, headers_with_content_length("42"));
+        assert_eq!(response.framing(true), BodyFraming::Length(0));
+    }
+
+    #[test]
+    fn test_framing_detects_chunked() {
+        let mut headers = Headers::new();
+        let value: Vec<u8> = "chunked".as_bytes().iter().cloned().collect();
+        headers.insert("Transfer-Encoding", &value).unwrap();
+
+        let response = response_with_headers(200, headers);
+        assert_eq!(response.framing(false), BodyFraming::Chunked);
+    }
+
+    #[test]
+    fn test_from_raw() {
+        let headers_buf = b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\n";
+        let mut header_storage = [httparse::EMPTY_HEADER; 4];
+        let mut raw = httparse::Response::new(&mut header_storage);
+        raw.parse(headers_buf).unwrap();
+
+        let response = Response::from_raw(raw).unwrap();
+        assert_eq!(response.code, 200);
+        assert_eq!(response.reason, "OK");
+        assert_eq!(response.framing(false), BodyFraming::Length(5));
+    }
+}
@@ -0,0 +1,178 @@
+// A small rule-based pipeline for editing `Headers` in place before they go
+// out over the wire - the kind of thing a reverse proxy uses to inject
+// security headers onto upstream responses, or strip hop-by-hop headers,
+// without the rest of the forwarding code needing to know the details.
+
+use std::io;
+use std::str;
+
+use ::headers::Headers;
+
+#[derive(Debug, Clone)]
+enum HeaderOp {
+    // Add a header, leaving any existing occurrences of it alone.
+    Add(String, Vec<u8>),
+    // Replace every existing occurrence of a header with a single new value.
+    Override(String, Vec<u8>),
+    // Drop every occurrence of a header.
+    Remove(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct HeaderRewriter {
+    rules: Vec<HeaderOp>,
+}
+
+impl HeaderRewriter {
+    pub fn new() -> HeaderRewriter {
+        HeaderRewriter { rules: Vec::new() }
+    }
+
+    pub fn add<S: Into<String>>(mut self, name: S, value: Vec<u8>) -> HeaderRewriter {
+        self.rules.push(HeaderOp::Add(name.into(), value));
+        self
+    }
+
+    pub fn set<S: Into<String>>(mut self, name: S, value: Vec<u8>) -> HeaderRewriter {
+        self.rules.push(HeaderOp::Override(name.into(), value));
+        self
+    }
+
+    pub fn remove<S: Into<String>>(mut self, name: S) -> HeaderRewriter {
+        self.rules.push(HeaderOp::Remove(name.into()));
+        self
+    }
+
+    // Rules for a reverse proxy that wants to harden upstream responses:
+    // injects common security headers and strips headers that only make
+    // sense hop-by-hop.
+    pub fn security_headers() -> HeaderRewriter {
+        HeaderRewriter::new()
+            .set("X-Frame-Options", b"SAMEORIGIN".to_vec())
+            .set("X-Content-Type-Options", b"nosniff".to_vec())
+            .set("Permissions-Policy", b"geolocation=(), microphone=(), camera=()".to_vec())
+            .set("Strict-Transport-Security", b"max-age=63072000; includeSubDomains".to_vec())
+            .remove("Proxy-Authenticate")
+            .remove("Proxy-Authorization")
+            .remove("Keep-Alive")
+    }
+
+    // Applies every rule to `headers` in order. If this looks like a
+    // WebSocket upgrade handshake, additions/overrides are skipped entirely
+    // so rules like `security_headers` don't inject anything that would
+    // break the upgrade (removals still run, since hop-by-hop stripping
+    // lists are expected to exclude `Connection`/`Upgrade` themselves).
+    pub fn apply(&self, headers: &mut Headers) -> io::Result<()> {
+        let upgrading = is_websocket_upgrade(headers);
+
+        for rule in &self.rules {
+            match *rule {
+                HeaderOp::Add(ref name, ref value) => {
+                    if upgrading {
+                        continue;
+                    }
+                    try!(headers.insert(name, value));
+                },
+                HeaderOp::Override(ref name, ref value) => {
+                    if upgrading {
+                        continue;
+                    }
+                    headers.remove(name);
+                    try!(headers.insert(name, value));
+                },
+                HeaderOp::Remove(ref name) => {
+                    headers.remove(name);
+                },
+            }
+        }
+
+        Ok(())
+    }
+}
+
+pub fn is_websocket_upgrade(headers: &Headers) -> bool {
+    let connection_upgrade = headers.get("connection")
+        .and_then(|v| str::from_utf8(v).ok())
+        .map(|v| v.split(',').any(|part| part.trim().eq_ignore_ascii_case("upgrade")))
+        .unwrap_or(false);
+
+    let upgrade_websocket = headers.get("upgrade")
+        .and_then(|v| str::from_utf8(v).ok())
+        .map(|v| v.eq_ignore_ascii_case("websocket"))
+        .unwrap_or(false);
+
+    connection_upgrade && upgrade_websocket
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::headers::Headers;
+
+    fn header(headers: &mut Headers, name: &str, value: &str) {
+        let value: Vec<u8> = value.as_bytes().iter().cloned().collect();
+        headers.insert(name, &value).unwrap();
+    }
+
+    #[test]
+    fn test_security_headers_injects_on_normal_response() {
+        let mut headers = Headers::new();
+        HeaderRewriter::security_headers().apply(&mut headers).unwrap();
+
+        assert_eq!(headers.get("X-Frame-Options"), Some(&b"SAMEORIGIN".to_vec()));
+        assert_eq!(headers.get("X-Content-Type-Options"), Some(&b"nosniff".to_vec()));
+        assert!(headers.get("Strict-Transport-Security").is_some());
+    }
+
+    #[test]
+    fn test_security_headers_overrides_existing_value() {
+        let mut headers = Headers::new();
+        header(&mut headers, "X-Frame-Options", "DENY");
+
+        HeaderRewriter::security_headers().apply(&mut headers).unwrap();
+
+        assert_eq!(headers.get("X-Frame-Options"), Some(&b"SAMEORIGIN".to_vec()));
+    }
+
+    #[test]
+    fn test_apply_skips_adds_and_overrides_on_websocket_upgrade() {
+        let mut headers = Headers::new();
+        header(&mut headers, "Connection", "upgrade");
+        header(&mut headers, "Upgrade", "websocket");
+        header(&mut headers, "Proxy-Authenticate", "Basic");
+
+        HeaderRewriter::security_headers()
+            .add("X-Added", b"yes".to_vec())
+            .apply(&mut headers)
+            .unwrap();
+
+        assert!(headers.get("X-Added").is_none());
+        assert!(headers.get("X-Frame-Options").is_none());
+        assert!(headers.get("Strict-Transport-Security").is_none());
+        // Removals still run even while upgrading.
+        assert!(headers.get("Proxy-Authenticate").is_none());
+    }
+
+    #[test]
+    fn test_is_websocket_upgrade_true() {
+        let mut headers = Headers::new();
+        header(&mut headers, "Connection", "Upgrade");
+        header(&mut headers, "Upgrade", "websocket");
+
+        assert!(is_websocket_upgrade(&headers));
+    }
+
+    #[test]
+    fn test_is_websocket_upgrade_false_without_upgrade_header() {
+        let mut headers = Headers::new();
+        header(&mut headers, "Connection", "upgrade");
+
+        assert!(!is_websocket_upgrade(&headers));
+    }
+
+    #[test]
+    fn test_is_websocket_upgrade_false_for_plain_response() {
+        let headers = Headers::new();
+        assert!(!is_websocket_upgrade(&headers));
+    }
+}
@@ -3,21 +3,72 @@ extern crate url;
 extern crate mioco;
 
 use std::io::prelude::*;
-use std::str::FromStr;
-use std::net;
+use std::net::{self, ToSocketAddrs};
 use std::str;
 use std::io;
+use std::cmp;
+use std::thread;
 
 use self::mioco::tcp::TcpStream;
+use self::mioco::sync::mpsc;
+
+// Default number of times to cycle through the resolved addresses before
+// giving up on a connection.
+const DEFAULT_CONNECT_RETRIES: usize = 2;
+
+// `ToSocketAddrs::to_socket_addrs` does a blocking call out to the system
+// resolver, which would stall the whole mioco event loop if run inline.
+// Hand it off to a plain OS thread and block the calling coroutine (not the
+// loop) on a mioco-aware channel until it's done.
+fn resolve(domain: &str, port: u16) -> io::Result<Vec<net::SocketAddr>> {
+    let domain = domain.to_owned();
+    let (tx, rx) = mpsc::channel();
+
+    thread::spawn(move || {
+        let result = (domain.as_str(), port).to_socket_addrs()
+            .map(|addrs| addrs.collect::<Vec<_>>());
+        let _ = tx.send(result);
+    });
+
+    match rx.recv() {
+        Ok(result) => result,
+        Err(_) => Err(io::Error::new(io::ErrorKind::Other, "DNS resolver thread went away")),
+    }
+}
+
+// Relays bytes between `downstream` and `upstream` in both directions, for
+// CONNECT tunnels and upgraded (e.g. WebSocket) connections where
+// request/response framing no longer applies. Returns once upstream closes;
+// a downstream close only ends the downstream-to-upstream relay, and the
+// upstream socket is not shut down when that happens.
+// `pending_downstream` is any body bytes already read from upstream while
+// buffering its headers that now need to go out before fresh reads do.
+fn tunnel(downstream: &mut TcpStream, upstream: &mut TcpStream, pending_downstream: &[u8]) -> io::Result<()> {
+    if !pending_downstream.is_empty() {
+        try!(downstream.write_all(pending_downstream));
+    }
+
+    let mut downstream_upload = try!(downstream.try_clone());
+    let mut upstream_upload = try!(upstream.try_clone());
+
+    mioco::spawn(move || -> io::Result<()> {
+        io::copy(&mut downstream_upload, &mut upstream_upload).map(|_| ())
+    });
+
+    io::copy(upstream, downstream).map(|_| ())
+}
 
 use ::headers::Headers;
+use ::response::{Response, BodyFraming};
+use ::chunked;
+use ::rewrite::{HeaderRewriter, is_websocket_upgrade};
 
 #[derive(Debug)]
 pub struct Request<'buf> {
     pub url: url::Url,
     pub method: &'buf str,
     pub version: u8,
-    pub headers: Headers<'buf>,
+    pub headers: Headers,
 }
 
 fn url_is_relative(url: &str) -> bool {
@@ -46,55 +97,170 @@ fn url_is_relative(url: &str) -> bool {
 }
 
 impl<'buf, 'headers> Request<'buf> {
-    pub fn from_raw(request: httparse::Request<'buf, 'headers>) -> Request<'buf> {
+    pub fn from_raw(request: httparse::Request<'buf, 'headers>) -> io::Result<Request<'buf>> {
         let path = request.path.unwrap();
+        let method = request.method.unwrap();
         let mut url = Vec::new();
-        let headers = Headers::from_raw(request.headers);
-
-        if url_is_relative(path) {
-            // FIXME: from the listening port, tell if it's secure or not for
-            // the correct scheme.
-            let secure = false;
-            if secure {
-                url.extend("https://".as_bytes());
-            } else {
-                url.extend("http://".as_bytes());
-            }
+        let headers = try!(Headers::from_raw(request.headers));
+
+        if method.eq_ignore_ascii_case("CONNECT") {
+            // CONNECT's request-target is authority-form (`host:port`, no
+            // scheme or path), which `Url::parse` can't make sense of on its
+            // own. Tack on a throwaway scheme so `host_str()`/`port()` still
+            // work for `connect()` to dial out to.
+            url.extend("connect://".as_bytes());
+            url.extend(path.as_bytes());
+        } else {
+            if url_is_relative(path) {
+                // FIXME: from the listening port, tell if it's secure or not for
+                // the correct scheme.
+                let secure = false;
+                if secure {
+                    url.extend("https://".as_bytes());
+                } else {
+                    url.extend("http://".as_bytes());
+                }
 
-            // FIXME: handle Host header missing
-            url.extend(headers.get("Host").unwrap());
+                // FIXME: handle Host header missing
+                url.extend(headers.get("Host").unwrap());
+            }
+            url.extend(path.as_bytes());
         }
-        url.extend(path.as_bytes());
 
-        Request {
+        Ok(Request {
             headers: headers,
             // FIXME: need to handle gluing the Host header to the URL if it's
             // relative.
             url: url::Url::parse(str::from_utf8(&url).unwrap()).unwrap(),
-            method: request.method.unwrap(),
+            method: method,
             version: request.version.unwrap(),
-        }
+        })
     }
 
     // FIXME: Every method from here onwards should be moved onto traits or a
     // client library or something, not here.
 
-    pub fn forward<S: Write>(&self, downstream: &mut S, body: Vec<u8>) {
-        match self.connect() {
-            Ok(mut upstream) => {
-                upstream.write_all(&self.serialize()).unwrap();
-                upstream.write_all(&body).unwrap();
+    pub fn forward(&self, downstream: &mut TcpStream, body: Vec<u8>,
+                   request_rewriter: &HeaderRewriter, response_rewriter: &HeaderRewriter) {
+        let mut upstream = match self.connect() {
+            Ok(upstream) => upstream,
+            Err(_) => {
+                if let Err(e) = downstream.write_all(b"HTTP/1.1 501 Not Implemented\r\nContent-Length: 6\r\n\r\nSorry\n") {
+                    println!("Error writing 501 to downstream: {}", e);
+                }
+                return;
+            }
+        };
+
+        // CONNECT never gets a parsed response of its own - once the tunnel
+        // to the target is open we just relay bytes in both directions.
+        if self.method.eq_ignore_ascii_case("CONNECT") {
+            if let Err(e) = downstream.write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n") {
+                println!("Error writing CONNECT response to downstream: {}", e);
+                return;
+            }
+            if let Err(e) = tunnel(downstream, &mut upstream, &[]) {
+                println!("Error tunneling CONNECT: {}", e);
+            }
+            return;
+        }
+
+        let mut request_headers = self.headers.clone();
+        if let Err(e) = request_rewriter.apply(&mut request_headers) {
+            println!("Error rewriting request headers: {}", e);
+        }
+
+        if let Err(e) = upstream.write_all(&self.serialize_with(&request_headers)) {
+            println!("Error writing request to upstream: {}", e);
+            return;
+        }
+        if let Err(e) = upstream.write_all(&body) {
+            println!("Error writing request body to upstream: {}", e);
+            return;
+        }
+
+        let mut buf = Vec::with_capacity(4096);
+        let (mut response, consumed) = match Response::read_from(&mut upstream, &mut buf) {
+            Ok(result) => result,
+            Err(e) => {
+                println!("Error reading upstream response: {}", e);
+                return;
+            }
+        };
+
+        if let Err(e) = response_rewriter.apply(&mut response.headers) {
+            println!("Error rewriting response headers: {}", e);
+        }
+
+        if let Err(e) = downstream.write_all(&response.serialize()) {
+            println!("Error writing response to downstream: {}", e);
+            return;
+        }
+
+        // Anything read past the headers while filling `buf` is already the
+        // start of the body, so it needs to go out before we read any more.
+        let leftover = &buf[consumed..];
+
+        // A successful protocol upgrade (e.g. a WebSocket handshake) means
+        // there's no more request/response framing to respect - switch to a
+        // raw relay for the rest of the connection's life.
+        if response.code == 101 && is_websocket_upgrade(&response.headers) {
+            if let Err(e) = tunnel(downstream, &mut upstream, leftover) {
+                println!("Error tunneling upgraded connection: {}", e);
+            }
+            return;
+        }
+
+        let is_head = self.method.eq_ignore_ascii_case("HEAD");
+
+        match response.framing(is_head) {
+            BodyFraming::Length(len) => {
+                let mut remaining = len.saturating_sub(leftover.len());
+                if let Err(e) = downstream.write_all(&leftover[..cmp::min(leftover.len(), len)]) {
+                    println!("Error writing response body to downstream: {}", e);
+                    return;
+                }
 
                 let mut buffer = [0; 65535];
+                while remaining > 0 {
+                    let want = cmp::min(remaining, buffer.len());
+                    match upstream.read(&mut buffer[..want]) {
+                        Ok(0) => break,
+                        Ok(n) => {
+                            if let Err(e) = downstream.write_all(&buffer[..n]) {
+                                println!("Error writing response body to downstream: {}", e);
+                                break;
+                            }
+                            remaining -= n;
+                        },
+                        Err(e) => {
+                            println!("Error {}", e);
+                            break
+                        }
+                    }
+                }
+            },
+            BodyFraming::Chunked => {
+                let mut prefixed = io::Cursor::new(leftover.to_vec()).chain(&mut upstream);
+                if let Err(e) = chunked::copy_chunked(&mut prefixed, downstream) {
+                    println!("Error {}", e);
+                }
+            },
+            BodyFraming::Close => {
+                if let Err(e) = downstream.write_all(leftover) {
+                    println!("Error writing response body to downstream: {}", e);
+                    return;
+                }
 
-                // FIXME: actually parse the response here.
+                let mut buffer = [0; 65535];
                 loop {
                     match upstream.read(&mut buffer) {
-                        Ok(0) => {
-                            break
-                        },
+                        Ok(0) => break,
                         Ok(n) => {
-                            downstream.write_all(&buffer[..n]).unwrap();
+                            if let Err(e) = downstream.write_all(&buffer[..n]) {
+                                println!("Error writing response body to downstream: {}", e);
+                                break;
+                            }
                         },
                         Err(e) => {
                             println!("Error {}", e);
@@ -102,28 +268,33 @@ impl<'buf, 'headers> Request<'buf> {
                         }
                     }
                 }
-            },
-            Err(_) => {
-                downstream.write_all(b"HTTP/1.1 501 Internal Server Error\r\nContent-Length: 6\r\nSorry\n").unwrap();
-                return;
             }
         }
     }
 
     // FIXME: is there a serialization trait of some sort I can implement?
     pub fn serialize(&self) -> Vec<u8> {
+        self.serialize_with(&self.headers)
+    }
+
+    fn serialize_with(&self, headers: &Headers) -> Vec<u8> {
         let mut out = Vec::<u8>::with_capacity(65535);
 
         let reqline = format!("{} {} HTTP/1.{}\r\n", self.method, self.url.path(), self.version);
         out.extend(reqline.as_bytes());
-        out.extend(self.headers.serialize());
+        out.extend(headers.serialize());
         out.extend(b"\r\n");
 
         out
     }
 
     pub fn connect(&self) -> io::Result<TcpStream> {
-        let domain = self.url.host_str().unwrap();
+        self.connect_with_retries(DEFAULT_CONNECT_RETRIES)
+    }
+
+    pub fn connect_with_retries(&self, retries: usize) -> io::Result<TcpStream> {
+        let domain = try!(self.url.host_str()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "request URL has no host")));
         let port = match self.url.port() {
             Some(port) => port,
             None => {
@@ -135,22 +306,84 @@ impl<'buf, 'headers> Request<'buf> {
             }
         };
 
-        // FIXME: DNS Lookup. net::lookup_addrs is unstable and also blocking.
-        let ip = net::IpAddr::from_str(domain).unwrap();
-        let addr = net::SocketAddr::new(ip, port);
+        let addrs = try!(resolve(domain, port));
+        if addrs.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::NotFound,
+                                       format!("no addresses found for {}", domain)));
+        }
 
-        // FIXME: configurable retry count
-        for _ in 0..2 {
-            match TcpStream::connect(&addr) {
-                Ok(conn) => {
-                    return Ok(conn);
-                },
-                Err(e) => {
-                    println!("failed to connect: {}", e);
+        let mut last_err = None;
+        for _ in 0..retries {
+            for addr in &addrs {
+                match TcpStream::connect(addr) {
+                    Ok(conn) => {
+                        return Ok(conn);
+                    },
+                    Err(e) => {
+                        println!("failed to connect to {}: {}", addr, e);
+                        last_err = Some(e);
+                    }
                 }
             }
         }
 
-        TcpStream::connect(&addr)
+        Err(last_err.unwrap_or_else(|| io::Error::new(io::ErrorKind::Other, "failed to connect")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate httparse;
+
+    use super::*;
+
+    #[test]
+    fn test_from_raw_connect_resolves_authority_form_target() {
+        let buf = b"CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\n\r\n";
+        let mut header_storage = [httparse::EMPTY_HEADER; 4];
+        let mut raw = httparse::Request::new(&mut header_storage);
+        raw.parse(buf).unwrap();
+
+        let request = Request::from_raw(raw).unwrap();
+        assert_eq!(request.method, "CONNECT");
+        assert_eq!(request.url.host_str(), Some("example.com"));
+        assert_eq!(request.url.port(), Some(443));
+    }
+
+    #[test]
+    fn test_resolve_numeric_literal_does_not_panic() {
+        // `resolve` hands the lookup off to a plain OS thread and blocks the
+        // calling coroutine on a mioco-aware channel, so it needs a mioco
+        // event loop under it - `mioco::start` provides one and returns the
+        // closure's result wrapped in a `thread::Result`.
+        let result = mioco::start(|| resolve("127.0.0.1", 80));
+
+        // A dotted-quad is resolved locally without touching the network,
+        // so this is safe to run anywhere and locks in the non-panicking
+        // `io::Result` contract `resolve` replaced `from_str().unwrap()` with.
+        let addrs = result.unwrap().unwrap();
+        assert!(addrs.iter().any(|addr| addr.ip() == "127.0.0.1".parse::<net::IpAddr>().unwrap()));
+    }
+
+    #[test]
+    fn test_connect_with_retries_returns_err_instead_of_panicking() {
+        // `connect_with_retries` resolves via `resolve` and dials out with
+        // `mioco::tcp::TcpStream`, both of which need to run on mioco's
+        // event loop rather than a bare test thread.
+        let result = mioco::start(|| {
+            let request = Request {
+                // Port 0 is never a live listener, so every candidate
+                // address fails to connect and every retry is exhausted
+                // quickly.
+                url: url::Url::parse("http://127.0.0.1:0").unwrap(),
+                method: "GET",
+                version: 1,
+                headers: Headers::new(),
+            };
+
+            request.connect_with_retries(1)
+        });
+
+        assert!(result.unwrap().is_err());
     }
 }